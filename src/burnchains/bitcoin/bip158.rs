@@ -0,0 +1,421 @@
+// Copyright (C) 2013-2020 Blockstack PBC, a public benefit corporation
+// Copyright (C) 2020 Stacks Open Internet Foundation
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! BIP158 "basic" compact block filters.
+//!
+//! A basic filter encodes the set of output scripts touched by a block as a
+//! Golomb-Rice–coded set (GCS). Membership is probabilistic: a query that is
+//! present is always reported present, while a query that is absent is
+//! reported present with a false-positive rate of `1/M`. A light client uses this to
+//! decide, without trusting a full node, whether a block is worth downloading.
+//!
+//! The parameters match the Bitcoin basic filter: the Golomb-Rice parameter is
+//! `P = 19` and the range modulus is `M = 784931`. Each item is mapped into the
+//! range `[0, N*M)` by keying SipHash-2-4 with the block hash, so filters are
+//! not malleable by a relaying peer.
+
+/// Golomb-Rice coding parameter for the basic filter.
+pub const BASIC_FILTER_P: u8 = 19;
+/// Range modulus for the basic filter.
+pub const BASIC_FILTER_M: u64 = 784931;
+
+/// A decoded-on-demand BIP158 GCS filter over a block's output scripts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// `CompactSize` item count followed by the Golomb-Rice bitstream.
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    /// Build a basic filter over `scripts`, keyed by `key` (the first 16 bytes
+    /// of the block hash). Duplicate scripts are collapsed, as the GCS is a set.
+    pub fn new(key: &[u8; 16], scripts: &[Vec<u8>]) -> BlockFilter {
+        // The GCS is a set, so size the range on the *distinct* item count N:
+        // both construction here and `match_any` must map into `[0, N*M)` with
+        // the same N, or a present script lands in a different range and never
+        // matches. Collapse duplicate scripts before computing N.
+        let mut distinct: Vec<&Vec<u8>> = scripts.iter().collect();
+        distinct.sort_unstable();
+        distinct.dedup();
+        let n = distinct.len() as u64;
+        let f = n.saturating_mul(BASIC_FILTER_M);
+
+        let mut hashes: Vec<u64> = distinct
+            .iter()
+            .map(|s| hash_to_range_with(key, s, f))
+            .collect();
+        hashes.sort_unstable();
+
+        let mut writer = GcsWriter::new(BASIC_FILTER_P);
+        let mut last = 0u64;
+        for h in hashes.iter() {
+            writer.write_value(*h - last);
+            last = *h;
+        }
+        let bitstream = writer.finish();
+
+        let mut content = encode_compact_size(n);
+        content.extend_from_slice(&bitstream);
+        BlockFilter { content }
+    }
+
+    /// Test whether any of `queries` is a possible member of the filter.
+    ///
+    /// `key` must be the same key used to construct the filter. Returns `true`
+    /// if at least one query is present (possibly a false positive), `false` if
+    /// every query is definitely absent.
+    pub fn match_any(&self, key: &[u8; 16], queries: &[Vec<u8>]) -> bool {
+        let (n, offset) = decode_compact_size(&self.content);
+        if n == 0 {
+            return false;
+        }
+        let f = n.saturating_mul(BASIC_FILTER_M);
+
+        let mut targets: Vec<u64> = queries
+            .iter()
+            .map(|q| hash_to_range_with(key, q, f))
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+
+        let mut reader = GcsReader::new(&self.content[offset..], BASIC_FILTER_P);
+        let mut value = 0u64;
+        let mut ti = 0;
+        let mut decoded = 0u64;
+        while ti < targets.len() {
+            if decoded == n {
+                return false;
+            }
+            value += match reader.read_value() {
+                Some(delta) => delta,
+                None => return false,
+            };
+            decoded += 1;
+            while ti < targets.len() && targets[ti] < value {
+                ti += 1;
+            }
+            if ti < targets.len() && targets[ti] == value {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Map `item` into `[0, f)` via SipHash-2-4 and the fixed-point multiply used
+/// by BIP158 to avoid a modulo bias.
+fn hash_to_range_with(key: &[u8; 16], item: &[u8], f: u64) -> u64 {
+    let mut k0 = [0u8; 8];
+    let mut k1 = [0u8; 8];
+    k0.copy_from_slice(&key[0..8]);
+    k1.copy_from_slice(&key[8..16]);
+    let h = siphash24(u64::from_le_bytes(k0), u64::from_le_bytes(k1), item);
+    (((h as u128) * (f as u128)) >> 64) as u64
+}
+
+/// Writer for a Golomb-Rice–coded bitstream, MSB-first within each byte.
+struct GcsWriter {
+    p: u8,
+    bytes: Vec<u8>,
+    cur: u8,
+    nbits: u8,
+}
+
+impl GcsWriter {
+    fn new(p: u8) -> GcsWriter {
+        GcsWriter {
+            p,
+            bytes: vec![],
+            cur: 0,
+            nbits: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        self.cur = (self.cur << 1) | (bit as u8);
+        self.nbits += 1;
+        if self.nbits == 8 {
+            self.bytes.push(self.cur);
+            self.cur = 0;
+            self.nbits = 0;
+        }
+    }
+
+    fn write_value(&mut self, value: u64) {
+        let quotient = value >> self.p;
+        for _ in 0..quotient {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for i in (0..self.p).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.cur <<= 8 - self.nbits;
+            self.bytes.push(self.cur);
+        }
+        self.bytes
+    }
+}
+
+/// Reader matching [`GcsWriter`]; yields `None` once the stream is exhausted.
+struct GcsReader<'a> {
+    p: u8,
+    bytes: &'a [u8],
+    pos: usize,
+    bit: u8,
+}
+
+impl<'a> GcsReader<'a> {
+    fn new(bytes: &'a [u8], p: u8) -> GcsReader<'a> {
+        GcsReader {
+            p,
+            bytes,
+            pos: 0,
+            bit: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        if self.pos >= self.bytes.len() {
+            return None;
+        }
+        let byte = self.bytes[self.pos];
+        let bit = (byte >> (7 - self.bit)) & 1 == 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+        Some(bit)
+    }
+
+    fn read_value(&mut self) -> Option<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..self.p {
+            remainder = (remainder << 1) | (self.read_bit()? as u64);
+        }
+        Some((quotient << self.p) + remainder)
+    }
+}
+
+/// Encode a Bitcoin `CompactSize` unsigned integer.
+fn encode_compact_size(value: u64) -> Vec<u8> {
+    if value < 0xfd {
+        vec![value as u8]
+    } else if value <= 0xffff {
+        let mut v = vec![0xfd];
+        v.extend_from_slice(&(value as u16).to_le_bytes());
+        v
+    } else if value <= 0xffff_ffff {
+        let mut v = vec![0xfe];
+        v.extend_from_slice(&(value as u32).to_le_bytes());
+        v
+    } else {
+        let mut v = vec![0xff];
+        v.extend_from_slice(&value.to_le_bytes());
+        v
+    }
+}
+
+/// Decode a `CompactSize` prefix, returning the value and the number of bytes
+/// consumed so the caller can locate the Golomb-Rice bitstream that follows. A
+/// truncated prefix (a marker byte with too few bytes behind it) decodes as
+/// `(0, 0)` so attacker-supplied `content` cannot panic a `pub` caller.
+fn decode_compact_size(bytes: &[u8]) -> (u64, usize) {
+    match bytes.first() {
+        None => (0, 0),
+        Some(&first) if first < 0xfd => (first as u64, 1),
+        Some(&0xfd) if bytes.len() >= 3 => {
+            let mut b = [0u8; 2];
+            b.copy_from_slice(&bytes[1..3]);
+            (u16::from_le_bytes(b) as u64, 3)
+        }
+        Some(&0xfe) if bytes.len() >= 5 => {
+            let mut b = [0u8; 4];
+            b.copy_from_slice(&bytes[1..5]);
+            (u32::from_le_bytes(b) as u64, 5)
+        }
+        Some(&0xff) if bytes.len() >= 9 => {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&bytes[1..9]);
+            (u64::from_le_bytes(b), 9)
+        }
+        Some(_) => (0, 0),
+    }
+}
+
+/// Bitcoin double-SHA256 over arbitrary bytes, used to hash a filter's content
+/// and to chain BIP157 filter headers.
+pub(crate) fn double_sha256(data: &[u8]) -> [u8; 32] {
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    let mut first = Sha256::new();
+    first.input(data);
+    let mut second = Sha256::new();
+    second.input(first.result());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&second.result());
+    out
+}
+
+/// SipHash-2-4 over `data`, matching the keying BIP158 uses for filter items.
+fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v0 = 0x736f_6d65_7073_6575 ^ k0;
+    let mut v1 = 0x646f_7261_6e64_6f6d ^ k1;
+    let mut v2 = 0x6c79_6765_6e65_7261 ^ k0;
+    let mut v3 = 0x7465_6462_7974_6573 ^ k1;
+
+    macro_rules! round {
+        () => {{
+            v0 = v0.wrapping_add(v1);
+            v1 = v1.rotate_left(13);
+            v1 ^= v0;
+            v0 = v0.rotate_left(32);
+            v2 = v2.wrapping_add(v3);
+            v3 = v3.rotate_left(16);
+            v3 ^= v2;
+            v0 = v0.wrapping_add(v3);
+            v3 = v3.rotate_left(21);
+            v3 ^= v0;
+            v2 = v2.wrapping_add(v1);
+            v1 = v1.rotate_left(17);
+            v1 ^= v2;
+            v2 = v2.rotate_left(32);
+        }};
+    }
+
+    let len = data.len();
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(chunk);
+        let m = u64::from_le_bytes(b);
+        v3 ^= m;
+        round!();
+        round!();
+        v0 ^= m;
+    }
+
+    let mut last = (len as u64 & 0xff) << 56;
+    for (i, byte) in chunks.remainder().iter().enumerate() {
+        last |= (*byte as u64) << (8 * i);
+    }
+    v3 ^= last;
+    round!();
+    round!();
+    v0 ^= last;
+
+    v2 ^= 0xff;
+    round!();
+    round!();
+    round!();
+    round!();
+
+    v0 ^ v1 ^ v2 ^ v3
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scripts(raw: &[&[u8]]) -> Vec<Vec<u8>> {
+        raw.iter().map(|s| s.to_vec()).collect()
+    }
+
+    #[test]
+    fn gcs_construct_then_probe() {
+        let key = [7u8; 16];
+        let members = scripts(&[b"\x6a\x69\x64alpha", b"\x6a\x69\x64beta", b"recipient-spk"]);
+        let filter = BlockFilter::new(&key, &members);
+
+        // Every member must be reported present.
+        for m in members.iter() {
+            assert!(filter.match_any(&key, &[m.clone()]));
+        }
+        // A script that was never inserted is (almost surely) absent.
+        assert!(!filter.match_any(&key, &scripts(&[b"not-in-the-block"])));
+    }
+
+    #[test]
+    fn gcs_handles_duplicate_scripts() {
+        // A block that reuses an address appears as duplicate output scripts.
+        // The distinct-count fix must keep those members matchable rather than
+        // sizing the range on the pre-dedup count and losing them.
+        let key = [0xabu8; 16];
+        let reused: Vec<u8> = b"reused-address-spk".to_vec();
+        let members = vec![
+            reused.clone(),
+            reused.clone(),
+            reused.clone(),
+            b"\x6a\x69\x64commit".to_vec(),
+        ];
+        let filter = BlockFilter::new(&key, &members);
+
+        assert!(filter.match_any(&key, &[reused]));
+        assert!(filter.match_any(&key, &scripts(&[b"\x6a\x69\x64commit"])));
+        assert!(!filter.match_any(&key, &scripts(&[b"absent"])));
+    }
+
+    #[test]
+    fn gcs_empty_filter_matches_nothing() {
+        let filter = BlockFilter::new(&[0u8; 16], &[]);
+        assert!(!filter.match_any(&[0u8; 16], &scripts(&[b"anything"])));
+    }
+
+    #[test]
+    fn match_any_tolerates_truncated_content() {
+        // A lone CompactSize marker with no following bytes must not panic.
+        for marker in [0xfdu8, 0xfe, 0xff] {
+            let filter = BlockFilter {
+                content: vec![marker],
+            };
+            assert!(!filter.match_any(&[0u8; 16], &scripts(&[b"anything"])));
+        }
+    }
+
+    #[test]
+    fn golomb_rice_roundtrip() {
+        let values = [0u64, 1, 18, 19, 20, 524287, 524288, 1_000_000];
+        let mut writer = GcsWriter::new(BASIC_FILTER_P);
+        for v in values.iter() {
+            writer.write_value(*v);
+        }
+        let bytes = writer.finish();
+
+        let mut reader = GcsReader::new(&bytes, BASIC_FILTER_P);
+        for v in values.iter() {
+            assert_eq!(reader.read_value(), Some(*v));
+        }
+    }
+
+    #[test]
+    fn siphash_matches_reference_vector() {
+        // SipHash-2-4 reference vector for the empty input with the
+        // canonical key 000102...0f (from the SipHash paper's test vectors).
+        let k0 = 0x0706050403020100;
+        let k1 = 0x0f0e0d0c0b0a0908;
+        assert_eq!(siphash24(k0, k1, &[]), 0x726fdb47dd0e0e31);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (C) 2013-2020 Blockstack PBC, a public benefit corporation
+// Copyright (C) 2020 Stacks Open Internet Foundation
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bitcoin burnchain driver backed by BIP157/158 compact block filters.
+//!
+//! Unlike the Stacks L1 driver, which consumes a full event stream from a
+//! trusted indexer, this backend follows an actual Bitcoin burnchain with a
+//! light-client footprint: it verifies the filter-header chain, fetches the
+//! per-block basic filter, and only downloads and fully parses a block when the
+//! filter indicates one of our watched scripts might be present. Otherwise it
+//! advances on the filter headers alone.
+
+use crate::types::chainstate::BurnchainHeaderHash;
+
+use super::{BurnchainBlock, BurnchainRecipient, Error, MagicBytes, Txid};
+
+pub mod bip158;
+
+use self::bip158::{double_sha256, BlockFilter};
+
+/// A Bitcoin block parsed far enough to extract burn operations. Only populated
+/// for blocks whose basic filter matched one of our watched scripts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitcoinBlock {
+    pub block_height: u64,
+    pub block_hash: BurnchainHeaderHash,
+    pub parent_block_hash: BurnchainHeaderHash,
+    pub txs: Vec<BitcoinTransaction>,
+    pub timestamp: u64,
+}
+
+/// A Bitcoin transaction carrying a burn operation: the `MagicBytes`-tagged
+/// `OP_RETURN` payload plus the outputs that paid the registered recipients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitcoinTransaction {
+    pub txid: Txid,
+    pub vtxindex: u32,
+    pub opcode: u8,
+    pub data: Vec<u8>,
+    pub burn_amount: u64,
+    pub recipients: Vec<BurnchainRecipient>,
+}
+
+/// The set of output scripts a node watches for on the Bitcoin burnchain: the
+/// `MagicBytes`-tagged `OP_RETURN` prefix plus the scripts of registered
+/// recipient addresses. Probed against each block's basic filter.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WatchedScripts {
+    scripts: Vec<Vec<u8>>,
+}
+
+impl WatchedScripts {
+    pub fn new() -> WatchedScripts {
+        WatchedScripts { scripts: vec![] }
+    }
+
+    /// Watch for an `OP_RETURN` output carrying our magic-byte prefix. Only the
+    /// prefix is committed, since that is the discriminator a burn op shares.
+    pub fn watch_magic(&mut self, magic: &MagicBytes) {
+        // OP_RETURN (0x6a) followed by the magic bytes; the pushdata length is
+        // left off so the prefix matches regardless of payload size.
+        let mut script = vec![0x6a];
+        script.extend_from_slice(magic.as_bytes());
+        self.scripts.push(script);
+    }
+
+    /// Watch for outputs paying `script_pubkey`, e.g. a registered
+    /// `BurnchainRecipient` address.
+    pub fn watch_script(&mut self, script_pubkey: Vec<u8>) {
+        self.scripts.push(script_pubkey);
+    }
+
+    /// Probe a block's basic filter, keyed by the block hash, for any watched
+    /// script. A `false` result means the block definitely holds none of our
+    /// operations and can be skipped with only its filter header verified.
+    pub fn probe(&self, block_hash: &BurnchainHeaderHash, filter: &BlockFilter) -> bool {
+        if self.scripts.is_empty() {
+            return false;
+        }
+        filter.match_any(&filter_key(block_hash), &self.scripts)
+    }
+}
+
+/// Derive the SipHash key for a block's basic filter: the first 16 bytes of the
+/// block hash, as specified by BIP158.
+fn filter_key(block_hash: &BurnchainHeaderHash) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&block_hash.as_bytes()[0..16]);
+    key
+}
+
+/// A BIP157 filter header: the running double-SHA256 hash chain committing to a
+/// block's basic filter and the previous filter header.
+pub type FilterHeader = [u8; 32];
+
+/// The filter hash committed by a header: the double-SHA256 of the filter's
+/// serialized content.
+pub fn filter_hash(filter: &BlockFilter) -> [u8; 32] {
+    double_sha256(&filter.content)
+}
+
+/// Extend the filter-header chain: `header_n = dSHA256(filter_hash_n || header_{n-1})`.
+pub fn next_filter_header(filter: &BlockFilter, prev_header: &FilterHeader) -> FilterHeader {
+    let mut preimage = Vec::with_capacity(64);
+    preimage.extend_from_slice(&filter_hash(filter));
+    preimage.extend_from_slice(prev_header);
+    double_sha256(&preimage)
+}
+
+/// A single entry of the verified filter-header chain: a burnchain block, its
+/// height, and the filter header the peer commits for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterCheckpoint {
+    pub block_height: u64,
+    pub block_hash: BurnchainHeaderHash,
+    pub filter_header: FilterHeader,
+}
+
+/// A peer able to serve BIP157/158 data to a light client: the filter-header
+/// chain, a block's basic filter, and the full block when we need to parse it.
+pub trait CompactFilterPeer {
+    /// The filter-header chain from just after `start_height` to the burnchain
+    /// tip, in ascending height order.
+    fn filter_header_chain(&self, start_height: u64) -> Result<Vec<FilterCheckpoint>, Error>;
+    /// The basic filter for `block_hash`.
+    fn basic_filter(&self, block_hash: &BurnchainHeaderHash) -> Result<BlockFilter, Error>;
+    /// Download and parse the burn operations in `block_hash`.
+    fn download_block(&self, block_hash: &BurnchainHeaderHash) -> Result<BitcoinBlock, Error>;
+}
+
+/// Light-client Bitcoin burnchain indexer. Follows the chain over compact block
+/// filters: it verifies the filter-header chain, probes each block's basic
+/// filter against the watched scripts, and only downloads and parses a block
+/// when a match is possible — otherwise advancing on the verified header alone.
+pub struct BitcoinIndexer<P: CompactFilterPeer> {
+    peer: P,
+    watched: WatchedScripts,
+}
+
+impl<P: CompactFilterPeer> BitcoinIndexer<P> {
+    pub fn new(peer: P, watched: WatchedScripts) -> BitcoinIndexer<P> {
+        BitcoinIndexer { peer, watched }
+    }
+
+    /// Sync from `start_height` (whose committed `start_header` the client
+    /// already trusts) to the tip, returning the `BurnchainBlock::Bitcoin`
+    /// blocks that carry a possible burn op. Blocks whose filter does not match
+    /// are skipped once their filter header is verified, giving low-bandwidth
+    /// burnchain following. A peer that serves a filter inconsistent with its
+    /// committed header is rejected with `Error::BurnchainPeerBroken`.
+    pub fn sync(
+        &self,
+        start_height: u64,
+        start_header: FilterHeader,
+    ) -> Result<Vec<BurnchainBlock>, Error> {
+        let chain = self.peer.filter_header_chain(start_height)?;
+        let mut prev_header = start_header;
+        let mut matched = vec![];
+        for checkpoint in chain.iter() {
+            let filter = self.peer.basic_filter(&checkpoint.block_hash)?;
+            // Verify the filter links into the committed header chain before we
+            // trust it enough to decide whether to skip the block.
+            if next_filter_header(&filter, &prev_header) != checkpoint.filter_header {
+                return Err(Error::BurnchainPeerBroken);
+            }
+            prev_header = checkpoint.filter_header;
+
+            if self.watched.probe(&checkpoint.block_hash, &filter) {
+                let block = self.peer.download_block(&checkpoint.block_hash)?;
+                matched.push(BurnchainBlock::Bitcoin(block));
+            }
+        }
+        Ok(matched)
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (C) 2013-2020 Blockstack PBC, a public benefit corporation
+// Copyright (C) 2020 Stacks Open Internet Foundation
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Stacks events parser.
+//!
+//! Turns the `stacks-node` events API stream for a Layer-1 block into a
+//! [`StacksHyperBlock`] of hyperchain operations, decoding and validating each
+//! operation body before it is carried into the sortition DB. Each event
+//! carries the magic bytes from its L1 `OP_RETURN`/print payload; the parser
+//! drops events whose magic does not belong to this subnet so several subnets
+//! anchored on one L1 do not collide in the sortition DB.
+
+use crate::types::chainstate::StacksBlockId;
+
+use super::{
+    Burnchain, BurnchainTransaction, Error, MagicBytes, StacksHyperBlock, StacksHyperOp,
+    StacksHyperOpType, Txid,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A raw operation event as delivered by the `stacks-node` events API, before
+/// it is decoded into a [`StacksHyperOp`]. `magic` is the subnet tag carried on
+/// the L1 event and `payload` is the opcode-tagged operation body.
+pub struct StacksHyperOpEvent {
+    pub txid: Txid,
+    pub in_block: StacksBlockId,
+    pub event_index: u32,
+    pub magic: MagicBytes,
+    pub payload: Vec<u8>,
+}
+
+impl StacksHyperOpEvent {
+    /// Decode and validate this event into a [`StacksHyperOp`].
+    pub fn into_op(&self) -> Result<StacksHyperOp, Error> {
+        let event = StacksHyperOpType::deserialize(&self.payload).map_err(Error::OpError)?;
+        event.check().map_err(Error::OpError)?;
+        Ok(StacksHyperOp {
+            txid: self.txid.clone(),
+            in_block: self.in_block.clone(),
+            opcode: event.opcode(),
+            event_index: self.event_index,
+            event,
+        })
+    }
+}
+
+/// Parse one L1 block's events into a [`StacksHyperBlock`], dropping any event
+/// whose magic bytes do not belong to `burnchain`'s subnet.
+pub fn parse_stacks_hyper_block(
+    burnchain: &Burnchain,
+    current_block: StacksBlockId,
+    parent_block: StacksBlockId,
+    block_height: u64,
+    events: &[StacksHyperOpEvent],
+) -> Result<StacksHyperBlock, Error> {
+    let mut ops = Vec::with_capacity(events.len());
+    for event in events.iter() {
+        if !burnchain.matches_magic(&event.magic) {
+            continue;
+        }
+        ops.push(event.into_op()?);
+    }
+    Ok(StacksHyperBlock {
+        current_block,
+        parent_block,
+        block_height,
+        ops,
+    })
+}
+
+/// Wrap a parsed op as the [`BurnchainTransaction`] the indexer feeds into the
+/// sortition DB.
+pub fn into_burnchain_tx(op: StacksHyperOp) -> BurnchainTransaction {
+    BurnchainTransaction::StacksBase(op)
+}
@@ -0,0 +1,103 @@
+// Copyright (C) 2013-2020 Blockstack PBC, a public benefit corporation
+// Copyright (C) 2020 Stacks Open Internet Foundation
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! L1 indexer: detecting reorgs of the Layer-1 Stacks chain and computing the
+//! sortition rollback needed to keep following the canonical branch.
+//!
+//! When an incoming [`StacksHyperBlock`] does not extend the stored tip, the L1
+//! has reorged. The indexer walks the incoming branch down to the last block
+//! that is also on the stored branch (the common ancestor), collects every
+//! stored op above it as orphaned, and raises [`Error::Reorg`] so the
+//! coordinator can roll the sortition DB back to the fork point before
+//! replaying the new canonical branch.
+
+use crate::types::chainstate::StacksBlockId;
+
+use super::{Error, StacksHyperBlock, StacksHyperOp};
+
+/// Read-only view of the processed L1 chain the indexer has stored, enough to
+/// walk back across a reorg to the last common ancestor.
+pub trait BurnchainDB {
+    /// The current stored sortition tip (the last processed L1 block).
+    fn tip(&self) -> StacksBlockId;
+    /// The parent of a processed block, if we have it.
+    fn parent_of(&self, block: &StacksBlockId) -> Option<StacksBlockId>;
+    /// Whether `block` is on the processed (stored) branch.
+    fn is_processed(&self, block: &StacksBlockId) -> bool;
+    /// The ops recorded for a processed block, in block order.
+    fn ops_at(&self, block: &StacksBlockId) -> Vec<StacksHyperOp>;
+}
+
+/// The rollback a reorg requires: the fork point to roll the sortition DB back
+/// to, and the ops on the abandoned branch that must be undone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rollback {
+    pub common_ancestor: StacksBlockId,
+    pub orphaned: Vec<StacksHyperOp>,
+}
+
+/// Validate that `incoming` extends the stored tip. Returns `Ok(())` when it
+/// does; on a reorg it computes the [`Rollback`] and returns it inside
+/// [`Error::Reorg`]. A branch that never rejoins the stored chain surfaces as
+/// [`Error::MissingParentBlock`], since we cannot reconcile it.
+pub fn validate_parent<DB: BurnchainDB>(
+    db: &DB,
+    incoming: &StacksHyperBlock,
+) -> Result<(), Error> {
+    let tip = db.tip();
+    if incoming.extends(&tip) {
+        return Ok(());
+    }
+
+    let Rollback {
+        common_ancestor,
+        orphaned,
+    } = find_rollback(db, incoming, &tip)?;
+    Err(Error::Reorg {
+        common_ancestor,
+        orphaned,
+    })
+}
+
+/// Walk the incoming branch down to the last block on the stored chain, then
+/// collect the stored ops above that ancestor as orphaned.
+fn find_rollback<DB: BurnchainDB>(
+    db: &DB,
+    incoming: &StacksHyperBlock,
+    tip: &StacksBlockId,
+) -> Result<Rollback, Error> {
+    // Descend the incoming branch until we reach a block already on the stored
+    // chain; that block is the common ancestor.
+    let mut cursor = incoming.parent_block.clone();
+    while !db.is_processed(&cursor) {
+        cursor = db.parent_of(&cursor).ok_or(Error::MissingParentBlock)?;
+    }
+    let common_ancestor = cursor;
+
+    // Everything on the stored branch above the ancestor is orphaned and must
+    // be rolled back before the canonical branch is replayed.
+    let mut orphaned = vec![];
+    let mut walk = tip.clone();
+    while walk != common_ancestor {
+        orphaned.extend(db.ops_at(&walk));
+        walk = db.parent_of(&walk).ok_or(Error::MissingParentBlock)?;
+    }
+
+    Ok(Rollback {
+        common_ancestor,
+        orphaned,
+    })
+}
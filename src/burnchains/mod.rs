@@ -14,7 +14,8 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-/// This module contains drivers and types for all burn chains we support.
+/// This module contains drivers and types for all burn chains we support:
+/// the Layer-1 Stacks hyperchain and, via compact block filters, Bitcoin.
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::default::Default;
@@ -25,6 +26,8 @@ use std::marker::PhantomData;
 
 use rusqlite::Error as sqlite_error;
 
+use crate::burnchains::bitcoin::{BitcoinBlock, BitcoinTransaction};
+
 use address::AddressHashMode;
 use chainstate::burn::operations::leader_block_commit::OUTPUTS_PER_COMMIT;
 use chainstate::burn::operations::BlockstackOperationType;
@@ -37,12 +40,17 @@ use net::neighbors::MAX_NEIGHBOR_BLOCK_DELAY;
 use util::db::Error as db_error;
 use util::hash::Hash160;
 use util::secp256k1::MessageSignature;
+use vm::types::QualifiedContractIdentifier;
 
 use crate::types::chainstate::PoxId;
 use crate::types::chainstate::StacksAddress;
 use crate::types::chainstate::{BlockHeaderHash, BurnchainHeaderHash, StacksBlockId};
 use crate::types::proof::TrieHash;
 
+/// Bitcoin burnchain driver. Follows an actual Bitcoin chain with a
+/// light-client footprint using BIP157/158 compact block filters, so a subnet
+/// node can sync sortition without a trusted full-node event feed.
+pub mod bitcoin;
 pub mod burnchain;
 pub mod db;
 /// Stacks events parser used to construct the L1 hyperchain operations.
@@ -94,6 +102,9 @@ pub struct BurnchainParameters {
     pub first_block_hash: BurnchainHeaderHash,
     pub first_block_timestamp: u32,
     pub initial_reward_start_block: u64,
+    /// Namespaces the subnet's events on a shared L1: only ops tagged with
+    /// these magic bytes belong to this burnchain.
+    pub magic_bytes: MagicBytes,
 }
 
 impl BurnchainParameters {
@@ -118,6 +129,7 @@ impl BurnchainParameters {
                 .unwrap(),
             first_block_timestamp: BITCOIN_MAINNET_FIRST_BLOCK_TIMESTAMP,
             initial_reward_start_block: BITCOIN_MAINNET_INITIAL_REWARD_START_BLOCK,
+            magic_bytes: MagicBytes::default(),
         }
     }
 
@@ -133,6 +145,7 @@ impl BurnchainParameters {
                 .unwrap(),
             first_block_timestamp: BITCOIN_TESTNET_FIRST_BLOCK_TIMESTAMP,
             initial_reward_start_block: BITCOIN_TESTNET_FIRST_BLOCK_HEIGHT - 10_000,
+            magic_bytes: MagicBytes::default(),
         }
     }
 
@@ -148,6 +161,7 @@ impl BurnchainParameters {
                 .unwrap(),
             first_block_timestamp: BITCOIN_REGTEST_FIRST_BLOCK_TIMESTAMP,
             initial_reward_start_block: BITCOIN_REGTEST_FIRST_BLOCK_HEIGHT,
+            magic_bytes: MagicBytes::default(),
         }
     }
 
@@ -191,11 +205,293 @@ pub struct BurnchainRecipient {
     pub amount: u64,
 }
 
+/// Opcode identifying a subnet block-commit operation on the L1.
+pub const HYPEROP_BLOCK_COMMIT: u8 = b'>';
+/// Opcode for an L1→L2 STX deposit.
+pub const HYPEROP_DEPOSIT_STX: u8 = b'd';
+/// Opcode for an L1→L2 fungible-token deposit.
+pub const HYPEROP_DEPOSIT_FT: u8 = b'f';
+/// Opcode for an L1→L2 non-fungible-token deposit.
+pub const HYPEROP_DEPOSIT_NFT: u8 = b'n';
+/// Opcode for an L2→L1 STX withdrawal.
+pub const HYPEROP_WITHDRAW_STX: u8 = b'w';
+/// Opcode for an L2→L1 fungible-token withdrawal.
+pub const HYPEROP_WITHDRAW_FT: u8 = b'g';
+/// Opcode for an L2→L1 non-fungible-token withdrawal.
+pub const HYPEROP_WITHDRAW_NFT: u8 = b'm';
+
 #[derive(Debug, PartialEq, Clone)]
 /// This is the inner type of the Layer-1 Stacks event,
 /// containing any operation specific data.
 pub enum StacksHyperOpType {
-    BlockCommit { subnet_block_hash: BlockHeaderHash },
+    BlockCommit {
+        subnet_block_hash: BlockHeaderHash,
+    },
+    /// Move STX from the L1 into the subnet, crediting `recipient`.
+    DepositStx {
+        amount: u128,
+        recipient: StacksAddress,
+    },
+    /// Move a fungible token held by `l1_contract` into the subnet.
+    DepositFt {
+        l1_contract: QualifiedContractIdentifier,
+        amount: u128,
+        recipient: StacksAddress,
+    },
+    /// Move a non-fungible token `id` held by `l1_contract` into the subnet.
+    DepositNft {
+        l1_contract: QualifiedContractIdentifier,
+        id: u128,
+        recipient: StacksAddress,
+    },
+    /// Release STX from the subnet back to `recipient` on the L1.
+    WithdrawStx {
+        amount: u128,
+        recipient: StacksAddress,
+    },
+    /// Release a fungible token back to `recipient` on the L1.
+    WithdrawFt {
+        l1_contract: QualifiedContractIdentifier,
+        amount: u128,
+        recipient: StacksAddress,
+    },
+    /// Release a non-fungible token `id` back to `recipient` on the L1.
+    WithdrawNft {
+        l1_contract: QualifiedContractIdentifier,
+        id: u128,
+        recipient: StacksAddress,
+    },
+}
+
+impl StacksHyperOpType {
+    /// The opcode byte identifying this operation in the L1 event stream.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            StacksHyperOpType::BlockCommit { .. } => HYPEROP_BLOCK_COMMIT,
+            StacksHyperOpType::DepositStx { .. } => HYPEROP_DEPOSIT_STX,
+            StacksHyperOpType::DepositFt { .. } => HYPEROP_DEPOSIT_FT,
+            StacksHyperOpType::DepositNft { .. } => HYPEROP_DEPOSIT_NFT,
+            StacksHyperOpType::WithdrawStx { .. } => HYPEROP_WITHDRAW_STX,
+            StacksHyperOpType::WithdrawFt { .. } => HYPEROP_WITHDRAW_FT,
+            StacksHyperOpType::WithdrawNft { .. } => HYPEROP_WITHDRAW_NFT,
+        }
+    }
+
+    /// Serialize the operation body (opcode-tagged) for inclusion in the
+    /// sortition DB. Amounts and ids are big-endian; the L1 asset contract is
+    /// length-prefixed and the recipient is a version byte followed by its
+    /// 20-byte hash, so the body parses unambiguously.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode()];
+        match self {
+            StacksHyperOpType::BlockCommit { subnet_block_hash } => {
+                bytes.extend_from_slice(subnet_block_hash.as_bytes());
+            }
+            StacksHyperOpType::DepositStx { amount, recipient }
+            | StacksHyperOpType::WithdrawStx { amount, recipient } => {
+                bytes.extend_from_slice(&amount.to_be_bytes());
+                serialize_recipient(&mut bytes, recipient);
+            }
+            StacksHyperOpType::DepositFt {
+                l1_contract,
+                amount,
+                recipient,
+            }
+            | StacksHyperOpType::WithdrawFt {
+                l1_contract,
+                amount,
+                recipient,
+            } => {
+                serialize_contract(&mut bytes, l1_contract);
+                bytes.extend_from_slice(&amount.to_be_bytes());
+                serialize_recipient(&mut bytes, recipient);
+            }
+            StacksHyperOpType::DepositNft {
+                l1_contract,
+                id,
+                recipient,
+            }
+            | StacksHyperOpType::WithdrawNft {
+                l1_contract,
+                id,
+                recipient,
+            } => {
+                serialize_contract(&mut bytes, l1_contract);
+                bytes.extend_from_slice(&id.to_be_bytes());
+                serialize_recipient(&mut bytes, recipient);
+            }
+        }
+        bytes
+    }
+
+    /// Parse an operation body produced by [`serialize`], so ops can round-trip
+    /// from the L1 event stream back into the sortition DB.
+    ///
+    /// [`serialize`]: StacksHyperOpType::serialize
+    pub fn deserialize(bytes: &[u8]) -> Result<StacksHyperOpType, op_error> {
+        let mut cursor = OpCursor::new(bytes);
+        let opcode = cursor.read_u8()?;
+        let op = match opcode {
+            HYPEROP_BLOCK_COMMIT => StacksHyperOpType::BlockCommit {
+                subnet_block_hash: BlockHeaderHash(cursor.read_array()?),
+            },
+            HYPEROP_DEPOSIT_STX => StacksHyperOpType::DepositStx {
+                amount: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            HYPEROP_WITHDRAW_STX => StacksHyperOpType::WithdrawStx {
+                amount: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            HYPEROP_DEPOSIT_FT => StacksHyperOpType::DepositFt {
+                l1_contract: cursor.read_contract()?,
+                amount: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            HYPEROP_WITHDRAW_FT => StacksHyperOpType::WithdrawFt {
+                l1_contract: cursor.read_contract()?,
+                amount: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            HYPEROP_DEPOSIT_NFT => StacksHyperOpType::DepositNft {
+                l1_contract: cursor.read_contract()?,
+                id: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            HYPEROP_WITHDRAW_NFT => StacksHyperOpType::WithdrawNft {
+                l1_contract: cursor.read_contract()?,
+                id: cursor.read_u128()?,
+                recipient: cursor.read_recipient()?,
+            },
+            _ => return Err(op_error::ParseError),
+        };
+        // A well-formed body is consumed exactly; trailing bytes mean the event
+        // is malformed and must not round-trip lossily.
+        if !cursor.is_empty() {
+            return Err(op_error::ParseError);
+        }
+        Ok(op)
+    }
+
+    /// Check that the operation is well-formed before the coordinator applies
+    /// it: value-transfers must move a positive amount and name a well-formed
+    /// recipient. A block-commit carries no amount and always passes.
+    pub fn check(&self) -> Result<(), op_error> {
+        match self {
+            StacksHyperOpType::BlockCommit { .. } => Ok(()),
+            StacksHyperOpType::DepositStx { amount, recipient }
+            | StacksHyperOpType::WithdrawStx { amount, recipient } => {
+                check_amount(*amount)?;
+                check_recipient(recipient)
+            }
+            StacksHyperOpType::DepositFt {
+                amount, recipient, ..
+            }
+            | StacksHyperOpType::WithdrawFt {
+                amount, recipient, ..
+            } => {
+                check_amount(*amount)?;
+                check_recipient(recipient)
+            }
+            StacksHyperOpType::DepositNft { recipient, .. }
+            | StacksHyperOpType::WithdrawNft { recipient, .. } => check_recipient(recipient),
+        }
+    }
+}
+
+/// Append a length-prefixed L1 asset-contract identifier to `bytes`.
+fn serialize_contract(bytes: &mut Vec<u8>, contract: &QualifiedContractIdentifier) {
+    let encoded = contract.to_string();
+    bytes.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(encoded.as_bytes());
+}
+
+/// Append a recipient's version byte and hash to `bytes`.
+fn serialize_recipient(bytes: &mut Vec<u8>, recipient: &StacksAddress) {
+    bytes.push(recipient.version);
+    bytes.extend_from_slice(recipient.bytes.as_bytes());
+}
+
+/// A value-transfer must move a strictly positive amount.
+fn check_amount(amount: u128) -> Result<(), op_error> {
+    if amount == 0 {
+        Err(op_error::ParseError)
+    } else {
+        Ok(())
+    }
+}
+
+/// A recipient must carry a non-burn hash so credited value is recoverable.
+fn check_recipient(recipient: &StacksAddress) -> Result<(), op_error> {
+    if recipient.bytes == Hash160([0u8; 20]) {
+        Err(op_error::ParseError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Bounds-checked reader over a serialized operation body. Any short read or
+/// malformed field surfaces as `op_error::ParseError`, matching how the rest of
+/// the operations layer reports unparseable input.
+struct OpCursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> OpCursor<'a> {
+    fn new(bytes: &'a [u8]) -> OpCursor<'a> {
+        OpCursor { bytes, pos: 0 }
+    }
+
+    /// Whether every byte of the body has been consumed.
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], op_error> {
+        let end = self.pos.checked_add(n).ok_or(op_error::ParseError)?;
+        if end > self.bytes.len() {
+            return Err(op_error::ParseError);
+        }
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, op_error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, op_error> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_u128(&mut self) -> Result<u128, op_error> {
+        let mut buf = [0u8; 16];
+        buf.copy_from_slice(self.take(16)?);
+        Ok(u128::from_be_bytes(buf))
+    }
+
+    fn read_array(&mut self) -> Result<[u8; 32], op_error> {
+        let mut buf = [0u8; 32];
+        buf.copy_from_slice(self.take(32)?);
+        Ok(buf)
+    }
+
+    fn read_recipient(&mut self) -> Result<StacksAddress, op_error> {
+        let version = self.read_u8()?;
+        let bytes = Hash160::from_bytes(self.take(20)?).ok_or(op_error::ParseError)?;
+        Ok(StacksAddress { version, bytes })
+    }
+
+    fn read_contract(&mut self) -> Result<QualifiedContractIdentifier, op_error> {
+        let len = self.read_u32()? as usize;
+        let raw = self.take(len)?;
+        let encoded = std::str::from_utf8(raw).map_err(|_| op_error::ParseError)?;
+        QualifiedContractIdentifier::parse(encoded).map_err(|_| op_error::ParseError)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -213,29 +509,36 @@ pub struct StacksHyperOp {
 /// Enum for wrapping Layer-1 operation providers for hyperchains
 pub enum BurnchainTransaction {
     StacksBase(StacksHyperOp),
+    Bitcoin(BitcoinTransaction),
 }
 
 impl BurnchainTransaction {
     pub fn txid(&self) -> Txid {
         match *self {
             BurnchainTransaction::StacksBase(ref tx) => tx.txid.clone(),
+            BurnchainTransaction::Bitcoin(ref tx) => tx.txid.clone(),
         }
     }
 
     pub fn vtxindex(&self) -> u32 {
         match *self {
             BurnchainTransaction::StacksBase(ref tx) => tx.event_index,
+            BurnchainTransaction::Bitcoin(ref tx) => tx.vtxindex,
         }
     }
 
     pub fn opcode(&self) -> u8 {
         match *self {
             BurnchainTransaction::StacksBase(ref tx) => tx.opcode,
+            BurnchainTransaction::Bitcoin(ref tx) => tx.opcode,
         }
     }
 
     pub fn get_burn_amount(&self) -> u64 {
-        0
+        match *self {
+            BurnchainTransaction::StacksBase(..) => 0,
+            BurnchainTransaction::Bitcoin(ref tx) => tx.burn_amount,
+        }
     }
 }
 
@@ -249,10 +552,150 @@ pub struct StacksHyperBlock {
     pub ops: Vec<StacksHyperOp>,
 }
 
+impl StacksHyperBlock {
+    /// The ordered merkle leaves committed by the block: an identity leaf at
+    /// index 0 (mirroring the reserved first leaf of a witness merkle root),
+    /// followed by each op's txid in block order.
+    fn ops_merkle_leaves(&self) -> Vec<[u8; 32]> {
+        let mut leaves = Vec::with_capacity(self.ops.len() + 1);
+        leaves.push(self.identity_leaf());
+        for op in self.ops.iter() {
+            leaves.push(op.txid.0);
+        }
+        leaves
+    }
+
+    /// The reserved leaf anchoring the block's own identity, so the root also
+    /// commits to which block and height the ops were included in: the
+    /// double-SHA256 of `current_block` and `block_height`.
+    fn identity_leaf(&self) -> [u8; 32] {
+        use sha2::Digest;
+        use sha2::Sha256;
+
+        let mut first = Sha256::new();
+        first.input(self.current_block.as_bytes());
+        first.input(&self.block_height.to_be_bytes());
+        let mut second = Sha256::new();
+        second.input(first.result());
+        let mut out = [0u8; 32];
+        out.copy_from_slice(&second.result());
+        out
+    }
+
+    /// Whether this block extends `tip`, i.e. its `parent_block` is the stored
+    /// sortition tip. When this is false the indexer must walk back to the last
+    /// common ancestor and raise [`Error::Reorg`] before replaying the new
+    /// canonical branch.
+    pub fn extends(&self, tip: &StacksBlockId) -> bool {
+        &self.parent_block == tip
+    }
+
+    /// A Bitcoin-style double-SHA256 merkle root over the block's ordered op
+    /// txids, giving a light client a short commitment to the op set.
+    pub fn ops_merkle_root(&self) -> [u8; 32] {
+        ops_merkle_root(self.ops_merkle_leaves())
+    }
+
+    /// The sibling hash path proving that the op at ordinal `position` within
+    /// the block's `ops` is committed by [`ops_merkle_root`]. Returns `None` if
+    /// the block has no op at that position.
+    ///
+    /// `position` is the op's index in `ops`, not its `StacksHyperOp.event_index`
+    /// field: the events parser filters ops by magic, so `event_index` values
+    /// are sparse and do not equal their position in the stored block. A caller
+    /// locates the op's position by scanning `ops`.
+    pub fn op_inclusion_proof(&self, position: u32) -> Option<Vec<[u8; 32]>> {
+        let leaf_index = (position as usize).checked_add(1)?;
+        let leaves = self.ops_merkle_leaves();
+        if leaf_index >= leaves.len() {
+            return None;
+        }
+        Some(ops_merkle_proof(leaves, leaf_index))
+    }
+
+    /// Recompute the merkle root from an op's `leaf` txid at ordinal `position`
+    /// (its index in `ops`, not its `event_index` field) and its sibling
+    /// `path`, and check it against `root`. This gives SPV-style inclusion
+    /// checks without the full op vector.
+    pub fn verify_op_inclusion(
+        leaf: [u8; 32],
+        position: u32,
+        path: &[[u8; 32]],
+        root: &[u8; 32],
+    ) -> bool {
+        let mut index = position as usize + 1;
+        let mut acc = leaf;
+        for sibling in path.iter() {
+            acc = if index % 2 == 0 {
+                double_sha256_pair(&acc, sibling)
+            } else {
+                double_sha256_pair(sibling, &acc)
+            };
+            index /= 2;
+        }
+        &acc == root
+    }
+}
+
+/// Bitcoin double-SHA256 of two concatenated merkle nodes.
+fn double_sha256_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    use sha2::Digest;
+    use sha2::Sha256;
+
+    let mut first = Sha256::new();
+    first.input(left);
+    first.input(right);
+    let mut second = Sha256::new();
+    second.input(first.result());
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&second.result());
+    out
+}
+
+/// Fold the leaves into a merkle root level-by-level, duplicating the last node
+/// of any odd-sized level (the standard Bitcoin rule).
+fn ops_merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return [0u8; 32];
+    }
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            let last = *level.last().unwrap();
+            level.push(last);
+        }
+        level = level
+            .chunks(2)
+            .map(|pair| double_sha256_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    level[0]
+}
+
+/// Collect the sibling path from `index` up to the root, applying the same
+/// odd-level duplication as [`ops_merkle_root`].
+fn ops_merkle_proof(mut level: Vec<[u8; 32]>, mut index: usize) -> Vec<[u8; 32]> {
+    let mut path = vec![];
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            let last = *level.last().unwrap();
+            level.push(last);
+        }
+        let sibling = index ^ 1;
+        path.push(level[sibling]);
+        index /= 2;
+        level = level
+            .chunks(2)
+            .map(|pair| double_sha256_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    path
+}
+
 #[derive(Debug, PartialEq, Clone)]
 /// Enum for wrapping Layer-1 blocks for hyperchains
 pub enum BurnchainBlock {
     StacksHyperBlock(StacksHyperBlock),
+    Bitcoin(BitcoinBlock),
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -278,6 +721,24 @@ pub struct Burnchain {
     pub first_block_timestamp: u32,
     pub pox_constants: PoxConstants,
     pub initial_reward_start_block: u64,
+    /// The magic bytes identifying this subnet's events on the L1. Several
+    /// independent subnets can be anchored on one L1 indexer by giving each a
+    /// distinct value, the way a network's `magic()` partitions peers.
+    pub magic_bytes: MagicBytes,
+}
+
+impl Burnchain {
+    /// The magic bytes tagging events that belong to this subnet.
+    pub fn magic_bytes(&self) -> MagicBytes {
+        self.magic_bytes.clone()
+    }
+
+    /// Whether an event carrying `magic` belongs to this subnet. The events
+    /// parser uses this to drop ops anchored on the same L1 but destined for a
+    /// different subnet before they reach the sortition DB.
+    pub fn matches_magic(&self, magic: &MagicBytes) -> bool {
+        &self.magic_bytes == magic
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -432,6 +893,14 @@ pub enum Error {
     UnknownBlock(BurnchainHeaderHash),
     NonCanonicalPoxId(PoxId, PoxId),
     CoordinatorClosed,
+    /// An incoming L1 block did not extend the stored tip: the chain reorged.
+    /// The indexer walked back to `common_ancestor`, and `orphaned` holds the
+    /// ops on the now-abandoned branch that the sortition DB must roll back
+    /// before the new canonical branch is replayed.
+    Reorg {
+        common_ancestor: StacksBlockId,
+        orphaned: Vec<StacksHyperOp>,
+    },
 }
 
 impl fmt::Display for Error {
@@ -456,6 +925,15 @@ impl fmt::Display for Error {
                 parent, child
             ),
             Error::CoordinatorClosed => write!(f, "ChainsCoordinator channel hung up"),
+            Error::Reorg {
+                common_ancestor,
+                orphaned,
+            } => write!(
+                f,
+                "L1 reorg: {} ops orphaned back to common ancestor {}",
+                orphaned.len(),
+                common_ancestor
+            ),
         }
     }
 }
@@ -478,6 +956,7 @@ impl error::Error for Error {
             Error::UnknownBlock(_) => None,
             Error::NonCanonicalPoxId(_, _) => None,
             Error::CoordinatorClosed => None,
+            Error::Reorg { .. } => None,
         }
     }
 }
@@ -0,0 +1,187 @@
+// Copyright (C) 2013-2020 Blockstack PBC, a public benefit corporation
+// Copyright (C) 2020 Stacks Open Internet Foundation
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::types::chainstate::{BlockHeaderHash, StacksBlockId};
+
+use super::{StacksHyperBlock, StacksHyperOp, StacksHyperOpType, Txid};
+
+/// Build a block-commit op whose txid leaf is `seed`-filled.
+fn test_op(seed: u8, event_index: u32) -> StacksHyperOp {
+    StacksHyperOp {
+        txid: Txid([seed; 32]),
+        in_block: StacksBlockId([0x11; 32]),
+        opcode: super::HYPEROP_BLOCK_COMMIT,
+        event_index,
+        event: StacksHyperOpType::BlockCommit {
+            subnet_block_hash: BlockHeaderHash([seed; 32]),
+        },
+    }
+}
+
+fn test_block(ops: Vec<StacksHyperOp>) -> StacksHyperBlock {
+    StacksHyperBlock {
+        current_block: StacksBlockId([0x22; 32]),
+        parent_block: StacksBlockId([0x11; 32]),
+        block_height: 1,
+        ops,
+    }
+}
+
+#[test]
+fn ops_merkle_root_is_deterministic_and_order_sensitive() {
+    let a = test_block(vec![test_op(1, 0), test_op(2, 1), test_op(3, 2)]);
+    let b = test_block(vec![test_op(1, 0), test_op(2, 1), test_op(3, 2)]);
+    assert_eq!(a.ops_merkle_root(), b.ops_merkle_root());
+
+    // Reordering the ops changes the commitment.
+    let reordered = test_block(vec![test_op(2, 0), test_op(1, 1), test_op(3, 2)]);
+    assert_ne!(a.ops_merkle_root(), reordered.ops_merkle_root());
+}
+
+#[test]
+fn op_inclusion_proof_verifies_for_every_op() {
+    // Exercise odd- and even-width levels by using four ops (leaves = 5 with
+    // the reserved anchor leaf, so levels of 5 and 3 nodes are padded).
+    let ops = vec![test_op(5, 0), test_op(6, 1), test_op(7, 2), test_op(8, 3)];
+    let block = test_block(ops.clone());
+    let root = block.ops_merkle_root();
+
+    for (i, op) in ops.iter().enumerate() {
+        let proof = block
+            .op_inclusion_proof(i as u32)
+            .expect("op should be in the block");
+        assert!(StacksHyperBlock::verify_op_inclusion(
+            op.txid.0, i as u32, &proof, &root
+        ));
+    }
+}
+
+#[test]
+fn op_inclusion_proof_rejects_wrong_leaf_and_root() {
+    let ops = vec![test_op(5, 0), test_op(6, 1)];
+    let block = test_block(ops.clone());
+    let root = block.ops_merkle_root();
+    let proof = block.op_inclusion_proof(0).unwrap();
+
+    // A tampered leaf does not recompute the committed root.
+    assert!(!StacksHyperBlock::verify_op_inclusion(
+        [0xff; 32], 0, &proof, &root
+    ));
+    // The correct leaf against the wrong root also fails.
+    assert!(!StacksHyperBlock::verify_op_inclusion(
+        ops[0].txid.0,
+        0,
+        &proof,
+        &[0u8; 32]
+    ));
+}
+
+#[test]
+fn op_inclusion_proof_out_of_range_is_none() {
+    let block = test_block(vec![test_op(5, 0)]);
+    assert!(block.op_inclusion_proof(1).is_none());
+    assert!(block.op_inclusion_proof(u32::MAX).is_none());
+}
+
+mod reorg {
+    use std::collections::HashMap;
+
+    use crate::types::chainstate::StacksBlockId;
+
+    use crate::burnchains::indexer::{validate_parent, BurnchainDB};
+    use crate::burnchains::Error;
+
+    use super::{test_block, test_op};
+
+    /// In-memory processed chain: child -> parent links plus per-block ops.
+    struct MockDB {
+        tip: StacksBlockId,
+        parents: HashMap<StacksBlockId, StacksBlockId>,
+        ops: HashMap<StacksBlockId, Vec<super::StacksHyperOp>>,
+    }
+
+    fn block_id(seed: u8) -> StacksBlockId {
+        StacksBlockId([seed; 32])
+    }
+
+    impl BurnchainDB for MockDB {
+        fn tip(&self) -> StacksBlockId {
+            self.tip.clone()
+        }
+        fn parent_of(&self, block: &StacksBlockId) -> Option<StacksBlockId> {
+            self.parents.get(block).cloned()
+        }
+        fn is_processed(&self, block: &StacksBlockId) -> bool {
+            *block == block_id(0) || self.parents.contains_key(block)
+        }
+        fn ops_at(&self, block: &StacksBlockId) -> Vec<super::StacksHyperOp> {
+            self.ops.get(block).cloned().unwrap_or_default()
+        }
+    }
+
+    /// Stored chain: 0 <- 1 <- 2 (tip), with one op recorded at block 2.
+    fn mock_db() -> MockDB {
+        let mut parents = HashMap::new();
+        parents.insert(block_id(1), block_id(0));
+        parents.insert(block_id(2), block_id(1));
+        let mut ops = HashMap::new();
+        ops.insert(block_id(2), vec![test_op(9, 0)]);
+        MockDB {
+            tip: block_id(2),
+            parents,
+            ops,
+        }
+    }
+
+    fn incoming(parent: u8, current: u8) -> super::StacksHyperBlock {
+        let mut block = test_block(vec![]);
+        block.parent_block = block_id(parent);
+        block.current_block = block_id(current);
+        block
+    }
+
+    #[test]
+    fn extending_the_tip_is_accepted() {
+        let db = mock_db();
+        assert!(validate_parent(&db, &incoming(2, 3)).is_ok());
+    }
+
+    #[test]
+    fn reorg_reports_ancestor_and_orphaned_ops() {
+        let db = mock_db();
+        // New block 3 builds on block 1, orphaning block 2.
+        match validate_parent(&db, &incoming(1, 3)) {
+            Err(Error::Reorg {
+                common_ancestor,
+                orphaned,
+            }) => {
+                assert_eq!(common_ancestor, block_id(1));
+                assert_eq!(orphaned, vec![test_op(9, 0)]);
+            }
+            other => panic!("expected reorg, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn branch_that_never_rejoins_is_missing_parent() {
+        let db = mock_db();
+        // Parent 7 is unknown to the stored chain and has no recorded parent.
+        match validate_parent(&db, &incoming(7, 8)) {
+            Err(Error::MissingParentBlock) => {}
+            other => panic!("expected MissingParentBlock, got {:?}", other),
+        }
+    }
+}